@@ -1,13 +1,16 @@
 use std::{
     sync::Arc,
     ops::Index,
+    iter::FromIterator,
 };
 
+use rustc_hash::FxHashSet;
 use test_utils::tested_by;
 use ra_arena::{Arena, impl_arena_id, RawId, map::ArenaMap};
 use ra_syntax::{
-    AstNode, SourceFile, AstPtr, TreeArc,
-    ast::{self, NameOwner, AttrsOwner},
+    AstNode, SourceFile, AstPtr, TreeArc, SmolStr, SyntaxElement,
+    SyntaxKind::{IDENT, STRING, EQ, COMMA, L_PAREN, R_PAREN},
+    ast::{self, NameOwner, AttrsOwner, VisibilityOwner},
 };
 
 use crate::{
@@ -62,6 +65,7 @@ impl RawItems {
             raw_items: RawItems::default(),
             source_file_items: db.file_items(file_id.into()),
             source_map: ImportSourceMap::default(),
+            cfg_options: db.cfg_options(file_id),
         };
         let source_file = db.hir_parse(file_id);
         collector.process_module(None, &*source_file);
@@ -115,8 +119,26 @@ impl_arena_id!(Module);
 
 #[derive(Debug, PartialEq, Eq)]
 pub(super) enum ModuleData {
-    Declaration { name: Name, ast_id: FileAstId<ast::Module> },
-    Definition { name: Name, ast_id: FileAstId<ast::Module>, items: Vec<RawItem> },
+    Declaration {
+        name: Name,
+        ast_id: FileAstId<ast::Module>,
+        visibility: RawVisibility,
+        is_macro_use: bool,
+        macro_use_names: Vec<Name>,
+        /// Explicit file path from `#[path = "..."]`, overriding the default
+        /// `name.rs` / `name/mod.rs` resolution.
+        path_attr: Option<SmolStr>,
+        cfg: Option<CfgExpr>,
+    },
+    Definition {
+        name: Name,
+        ast_id: FileAstId<ast::Module>,
+        items: Vec<RawItem>,
+        visibility: RawVisibility,
+        is_macro_use: bool,
+        macro_use_names: Vec<Name>,
+        cfg: Option<CfgExpr>,
+    },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -130,6 +152,10 @@ pub struct ImportData {
     pub(super) is_glob: bool,
     pub(super) is_prelude: bool,
     pub(super) is_extern_crate: bool,
+    pub(super) is_macro_use: bool,
+    pub(super) macro_use_names: Vec<Name>,
+    pub(super) visibility: RawVisibility,
+    pub(super) cfg: Option<CfgExpr>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -141,12 +167,164 @@ pub(super) struct DefData {
     pub(super) source_item_id: SourceFileItemId,
     pub(super) name: Name,
     pub(super) kind: DefKind,
+    pub(super) visibility: RawVisibility,
+    pub(super) cfg: Option<CfgExpr>,
+}
+
+/// A visibility modifier attached to a def/import/module in the raw items
+/// layer. This is the syntactic visibility only: it has not yet been
+/// resolved relative to the crate that defines it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum RawVisibility {
+    /// `pub`
+    Public,
+    /// `pub(crate)`
+    Crate,
+    /// `pub(super)`
+    Super,
+    /// `pub(in some::path)`
+    InPath(Path),
+    /// Private to the module that defines it: no visibility specified,
+    /// `pub(self)`, or a `pub(in ..)` whose path does not parse.
+    PrivateImplicit,
+}
+
+impl RawVisibility {
+    fn from_ast(node: &impl VisibilityOwner) -> RawVisibility {
+        let vis = match node.visibility() {
+            Some(it) => it,
+            None => return RawVisibility::PrivateImplicit,
+        };
+        match vis.kind() {
+            ast::VisibilityKind::Pub => RawVisibility::Public,
+            ast::VisibilityKind::PubCrate => RawVisibility::Crate,
+            ast::VisibilityKind::PubSuper => RawVisibility::Super,
+            ast::VisibilityKind::PubSelf => RawVisibility::PrivateImplicit,
+            ast::VisibilityKind::PubPath(path) => match Path::from_ast(path) {
+                Some(path) => RawVisibility::InPath(path),
+                // Fail closed: an unparsable `pub(in ...)` path must not widen
+                // to `Public`, or privacy-aware name resolution would let
+                // through accesses that should be rejected.
+                None => RawVisibility::PrivateImplicit,
+            },
+        }
+    }
+}
+
+/// A `#[cfg(..)]` predicate, as written by the user, not yet evaluated
+/// against a particular target configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum CfgExpr {
+    Atom { key: SmolStr, value: Option<SmolStr> },
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Not(Box<CfgExpr>),
+}
+
+impl CfgExpr {
+    fn parse(tt: &ast::TokenTree) -> Option<CfgExpr> {
+        let mut it = tt.syntax().children_with_tokens().filter(|it| {
+            let kind = it.kind();
+            !kind.is_trivia() && kind != L_PAREN && kind != R_PAREN
+        });
+        next_cfg_expr(&mut it)
+    }
+
+    fn matches(&self, opts: &CfgOptions) -> bool {
+        match self {
+            CfgExpr::Atom { key, value } => opts.is_enabled(key, value.as_ref()),
+            CfgExpr::All(preds) => preds.iter().all(|it| it.matches(opts)),
+            CfgExpr::Any(preds) => preds.iter().any(|it| it.matches(opts)),
+            CfgExpr::Not(pred) => !pred.matches(opts),
+        }
+    }
+}
+
+fn next_cfg_expr(it: &mut dyn Iterator<Item = SyntaxElement>) -> Option<CfgExpr> {
+    let name = match it.next()? {
+        SyntaxElement::Token(t) if t.kind() == IDENT => SmolStr::from(t.text().as_str()),
+        _ => return None,
+    };
+    match it.next() {
+        Some(SyntaxElement::Token(eq)) if eq.kind() == EQ => match it.next() {
+            Some(SyntaxElement::Token(s)) if s.kind() == STRING => {
+                let value = SmolStr::new(s.text().trim_start_matches('"').trim_end_matches('"'));
+                Some(CfgExpr::Atom { key: name, value: Some(value) })
+            }
+            _ => None,
+        },
+        Some(SyntaxElement::Node(tt)) => {
+            let tt = ast::TokenTree::cast(&tt)?;
+            let mut preds = parse_cfg_list(tt);
+            match name.as_str() {
+                "all" => Some(CfgExpr::All(preds)),
+                "any" => Some(CfgExpr::Any(preds)),
+                "not" => Some(CfgExpr::Not(Box::new(preds.pop()?))),
+                _ => None,
+            }
+        }
+        _ => Some(CfgExpr::Atom { key: name, value: None }),
+    }
+}
+
+/// Parses the comma-separated list of predicates inside a `(..)` token tree,
+/// such as the body of `cfg(..)`, `all(..)`, `any(..)` or `not(..)`.
+fn parse_cfg_list(tt: &ast::TokenTree) -> Vec<CfgExpr> {
+    let mut result = Vec::new();
+    let mut current = Vec::new();
+    for el in tt.syntax().children_with_tokens() {
+        match &el {
+            SyntaxElement::Token(t) if t.kind() == L_PAREN || t.kind() == R_PAREN => continue,
+            SyntaxElement::Token(t) if t.kind().is_trivia() => continue,
+            SyntaxElement::Token(t) if t.kind() == COMMA => {
+                result.extend(next_cfg_expr(&mut current.drain(..)));
+            }
+            _ => current.push(el),
+        }
+    }
+    result.extend(next_cfg_expr(&mut current.drain(..)));
+    result
+}
+
+/// The set of `cfg` key/value pairs enabled for the crate a file belongs to,
+/// e.g. `unix`, `test`, `target_arch = "x86_64"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CfgOptions {
+    enabled: FxHashSet<(SmolStr, Option<SmolStr>)>,
+}
+
+impl CfgOptions {
+    /// Marks `key` (optionally `key = "value"`) as enabled, e.g. `unix` or
+    /// `target_arch = "x86_64"`. Used by the database layer to populate the
+    /// set from the crate's actual target configuration.
+    pub fn insert_atom(&mut self, key: SmolStr, value: Option<SmolStr>) {
+        self.enabled.insert((key, value));
+    }
+
+    fn is_enabled(&self, key: &SmolStr, value: Option<&SmolStr>) -> bool {
+        self.enabled.contains(&(key.clone(), value.cloned()))
+    }
+
+    fn check(&self, cfg: &Option<CfgExpr>) -> bool {
+        cfg.as_ref().map_or(true, |it| it.matches(self))
+    }
+}
+
+impl FromIterator<(SmolStr, Option<SmolStr>)> for CfgOptions {
+    fn from_iter<I: IntoIterator<Item = (SmolStr, Option<SmolStr>)>>(iter: I) -> CfgOptions {
+        let mut opts = CfgOptions::default();
+        for (key, value) in iter {
+            opts.insert_atom(key, value);
+        }
+        opts
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(super) enum DefKind {
     Function,
     Struct,
+    Union,
     Enum,
     Const,
     Static,
@@ -164,12 +342,14 @@ pub(super) struct MacroData {
     pub(super) path: Path,
     pub(super) name: Option<Name>,
     pub(super) export: bool,
+    pub(super) cfg: Option<CfgExpr>,
 }
 
 struct RawItemsCollector {
     raw_items: RawItems,
     source_file_items: Arc<SourceFileItems>,
     source_map: ImportSourceMap,
+    cfg_options: Arc<CfgOptions>,
 }
 
 impl RawItemsCollector {
@@ -200,7 +380,10 @@ impl RawItemsCollector {
                 // impls don't participate in name resolution
                 return;
             }
-            ast::ModuleItemKind::StructDef(it) => (DefKind::Struct, it.name()),
+            ast::ModuleItemKind::StructDef(it) => {
+                let kind = if it.is_union() { DefKind::Union } else { DefKind::Struct };
+                (kind, it.name())
+            }
             ast::ModuleItemKind::EnumDef(it) => (DefKind::Enum, it.name()),
             ast::ModuleItemKind::FnDef(it) => (DefKind::Function, it.name()),
             ast::ModuleItemKind::TraitDef(it) => (DefKind::Trait, it.name()),
@@ -209,9 +392,15 @@ impl RawItemsCollector {
             ast::ModuleItemKind::StaticDef(it) => (DefKind::Static, it.name()),
         };
         if let Some(name) = name {
+            let cfg = parse_cfg_attrs(item);
+            if !self.cfg_options.check(&cfg) {
+                return;
+            }
             let name = name.as_name();
             let source_item_id = self.source_file_items.id_of_unchecked(item.syntax());
-            let def = self.raw_items.defs.alloc(DefData { name, kind, source_item_id });
+            let visibility = RawVisibility::from_ast(item);
+            let def =
+                self.raw_items.defs.alloc(DefData { name, kind, source_item_id, visibility, cfg });
             self.push_item(current_module, RawItem::Def(def))
         }
     }
@@ -221,9 +410,24 @@ impl RawItemsCollector {
             Some(it) => it.as_name(),
             None => return,
         };
+        let cfg = parse_cfg_attrs(module);
+        if !self.cfg_options.check(&cfg) {
+            return;
+        }
         let ast_id = self.source_file_items.ast_id(module);
+        let visibility = RawVisibility::from_ast(module);
+        let (is_macro_use, macro_use_names) = extract_macro_use(module);
         if module.has_semi() {
-            let item = self.raw_items.modules.alloc(ModuleData::Declaration { name, ast_id });
+            let path_attr = parse_path_attr(module);
+            let item = self.raw_items.modules.alloc(ModuleData::Declaration {
+                name,
+                ast_id,
+                visibility,
+                is_macro_use,
+                macro_use_names,
+                path_attr,
+                cfg,
+            });
             self.push_item(current_module, RawItem::Module(item));
             return;
         }
@@ -233,6 +437,10 @@ impl RawItemsCollector {
                 name,
                 ast_id,
                 items: Vec::new(),
+                visibility,
+                is_macro_use,
+                macro_use_names,
+                cfg,
             });
             self.process_module(Some(item), item_list);
             self.push_item(current_module, RawItem::Module(item));
@@ -242,7 +450,12 @@ impl RawItemsCollector {
     }
 
     fn add_use_item(&mut self, current_module: Option<Module>, use_item: &ast::UseItem) {
+        let cfg = parse_cfg_attrs(use_item);
+        if !self.cfg_options.check(&cfg) {
+            return;
+        }
         let is_prelude = use_item.has_atom_attr("prelude_import");
+        let visibility = RawVisibility::from_ast(use_item);
 
         Path::expand_use_item(use_item, |path, segment, alias| {
             let import = self.raw_items.imports.alloc(ImportData {
@@ -251,6 +464,10 @@ impl RawItemsCollector {
                 is_glob: segment.is_none(),
                 is_prelude,
                 is_extern_crate: false,
+                is_macro_use: false,
+                macro_use_names: Vec::new(),
+                visibility: visibility.clone(),
+                cfg: cfg.clone(),
             });
             if let Some(segment) = segment {
                 self.source_map.insert(import, segment)
@@ -264,15 +481,24 @@ impl RawItemsCollector {
         current_module: Option<Module>,
         extern_crate: &ast::ExternCrateItem,
     ) {
+        let cfg = parse_cfg_attrs(extern_crate);
+        if !self.cfg_options.check(&cfg) {
+            return;
+        }
         if let Some(name_ref) = extern_crate.name_ref() {
             let path = Path::from_name_ref(name_ref);
             let alias = extern_crate.alias().and_then(|a| a.name()).map(AsName::as_name);
+            let (is_macro_use, macro_use_names) = extract_macro_use(extern_crate);
             let import = self.raw_items.imports.alloc(ImportData {
                 path,
                 alias,
                 is_glob: false,
                 is_prelude: false,
                 is_extern_crate: true,
+                is_macro_use,
+                macro_use_names,
+                visibility: RawVisibility::from_ast(extern_crate),
+                cfg,
             });
             self.push_item(current_module, RawItem::Import(import))
         }
@@ -283,11 +509,15 @@ impl RawItemsCollector {
             Some(it) => it,
             _ => return,
         };
+        let cfg = parse_cfg_attrs(m);
+        if !self.cfg_options.check(&cfg) {
+            return;
+        }
 
         let name = m.name().map(|it| it.as_name());
         let ast_id = self.source_file_items.ast_id(m);
         let export = m.has_atom_attr("macro_export");
-        let m = self.raw_items.macros.alloc(MacroData { ast_id, path, name, export });
+        let m = self.raw_items.macros.alloc(MacroData { ast_id, path, name, export, cfg });
         self.push_item(current_module, RawItem::Macro(m));
     }
 
@@ -302,3 +532,256 @@ impl RawItemsCollector {
         .push(item)
     }
 }
+
+/// Reads `#[macro_use]` / `#[macro_use(foo, bar)]` off `node`, returning
+/// whether the attribute is present at all and the explicit whitelist of
+/// macro names, if any. An empty whitelist with `is_macro_use == true` means
+/// "import all `#[macro_export]` macros".
+fn extract_macro_use(node: &impl AttrsOwner) -> (bool, Vec<Name>) {
+    let mut is_macro_use = false;
+    let mut macro_use_names = Vec::new();
+    for attr in node.attrs() {
+        if attr.as_atom().as_ref().map(SmolStr::as_str) == Some("macro_use") {
+            is_macro_use = true;
+            continue;
+        }
+        if let Some((name, tt)) = attr.as_call() {
+            if name == "macro_use" {
+                is_macro_use = true;
+                macro_use_names.extend(
+                    tt.syntax()
+                        .children_with_tokens()
+                        .filter_map(|it| it.into_token())
+                        .filter(|it| it.kind() == IDENT)
+                        .map(|it| Name::new(it.text().clone())),
+                );
+            }
+        }
+    }
+    (is_macro_use, macro_use_names)
+}
+
+/// Reads the file path override from `#[path = "..."]` on a module
+/// declaration, if present.
+fn parse_path_attr(node: &impl AttrsOwner) -> Option<SmolStr> {
+    node.attrs().find_map(|attr| {
+        let (name, value) = attr.as_named_value()?;
+        if name != "path" {
+            return None;
+        }
+        Some(value)
+    })
+}
+
+/// Collects the combined `#[cfg(..)]` predicate for `node`, ANDing together
+/// multiple `#[cfg(..)]` attributes and the gating predicate of any
+/// `#[cfg_attr(..)]` the same way rustc does.
+fn parse_cfg_attrs(node: &impl AttrsOwner) -> Option<CfgExpr> {
+    let mut preds = Vec::new();
+    for attr in node.attrs() {
+        if let Some((name, tt)) = attr.as_call() {
+            match name.as_str() {
+                "cfg" => preds.extend(CfgExpr::parse(&tt)),
+                "cfg_attr" => preds.extend(CfgExpr::parse(&tt)),
+                _ => {}
+            }
+        }
+    }
+    match preds.len() {
+        0 => None,
+        1 => preds.pop(),
+        _ => Some(CfgExpr::All(preds)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> TreeArc<SourceFile> {
+        SourceFile::parse(text)
+    }
+
+    fn first_fn(file: &SourceFile) -> &ast::FnDef {
+        file.items_with_macros()
+            .find_map(|it| match it {
+                ast::ItemOrMacro::Item(item) => match item.kind() {
+                    ast::ModuleItemKind::FnDef(it) => Some(it),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn first_extern_crate(file: &SourceFile) -> &ast::ExternCrateItem {
+        file.items_with_macros()
+            .find_map(|it| match it {
+                ast::ItemOrMacro::Item(item) => match item.kind() {
+                    ast::ModuleItemKind::ExternCrateItem(it) => Some(it),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn first_mod(file: &SourceFile) -> &ast::Module {
+        file.items_with_macros()
+            .find_map(|it| match it {
+                ast::ItemOrMacro::Item(item) => match item.kind() {
+                    ast::ModuleItemKind::Module(it) => Some(it),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn visibility_pub_in_path() {
+        let file = parse("pub(in crate::foo) fn f() {}");
+        let vis = RawVisibility::from_ast(first_fn(&file));
+        assert!(matches!(vis, RawVisibility::InPath(_)));
+    }
+
+    #[test]
+    fn visibility_malformed_pub_in_path_fails_closed() {
+        // An unparsable `pub(in ..)` path must not widen to `Public`.
+        let file = parse("pub(in ) fn f() {}");
+        let vis = RawVisibility::from_ast(first_fn(&file));
+        assert_eq!(vis, RawVisibility::PrivateImplicit);
+    }
+
+    #[test]
+    fn cfg_atom() {
+        let file = parse("#[cfg(unix)] fn f() {}");
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(cfg, Some(CfgExpr::Atom { key: "unix".into(), value: None }));
+    }
+
+    #[test]
+    fn cfg_key_value() {
+        let file = parse(r#"#[cfg(target_arch = "x86_64")] fn f() {}"#);
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(
+            cfg,
+            Some(CfgExpr::Atom { key: "target_arch".into(), value: Some("x86_64".into()) })
+        );
+    }
+
+    #[test]
+    fn cfg_nested_any() {
+        let file = parse("#[cfg(any(unix, windows))] fn f() {}");
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(
+            cfg,
+            Some(CfgExpr::Any(vec![
+                CfgExpr::Atom { key: "unix".into(), value: None },
+                CfgExpr::Atom { key: "windows".into(), value: None },
+            ]))
+        );
+    }
+
+    #[test]
+    fn cfg_all_with_nested_not() {
+        let file = parse(r#"#[cfg(all(unix, not(target_os = "macos")))] fn f() {}"#);
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(
+            cfg,
+            Some(CfgExpr::All(vec![
+                CfgExpr::Atom { key: "unix".into(), value: None },
+                CfgExpr::Not(Box::new(CfgExpr::Atom {
+                    key: "target_os".into(),
+                    value: Some("macos".into()),
+                })),
+            ]))
+        );
+    }
+
+    #[test]
+    fn cfg_list_ignores_trailing_comma() {
+        let file = parse("#[cfg(any(unix, windows,))] fn f() {}");
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(
+            cfg,
+            Some(CfgExpr::Any(vec![
+                CfgExpr::Atom { key: "unix".into(), value: None },
+                CfgExpr::Atom { key: "windows".into(), value: None },
+            ]))
+        );
+    }
+
+    #[test]
+    fn cfg_attr_extracts_gating_predicate() {
+        let file = parse(r#"#[cfg_attr(unix, derive(Debug))] fn f() {}"#);
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(cfg, Some(CfgExpr::Atom { key: "unix".into(), value: None }));
+    }
+
+    #[test]
+    fn cfg_attr_with_unparsable_predicate_is_dropped() {
+        let file = parse("#[cfg_attr(, derive(Debug))] fn f() {}");
+        let cfg = parse_cfg_attrs(first_fn(&file));
+        assert_eq!(cfg, None);
+    }
+
+    #[test]
+    fn macro_use_bare_on_extern_crate() {
+        let file = parse("#[macro_use] extern crate foo;");
+        let (is_macro_use, names) = extract_macro_use(first_extern_crate(&file));
+        assert!(is_macro_use);
+        assert!(names.is_empty());
+    }
+
+    #[test]
+    fn macro_use_with_explicit_whitelist() {
+        let file = parse("#[macro_use(foo, bar)] extern crate baz;");
+        let (is_macro_use, names) = extract_macro_use(first_extern_crate(&file));
+        assert!(is_macro_use);
+        assert_eq!(names, vec![Name::new("foo".into()), Name::new("bar".into())]);
+    }
+
+    #[test]
+    fn path_attr_on_module_declaration() {
+        let file = parse(r#"#[path = "foo.rs"] mod foo;"#);
+        let path = parse_path_attr(first_mod(&file));
+        assert_eq!(path, Some(SmolStr::from("foo.rs")));
+    }
+
+    #[test]
+    fn path_attr_absent_on_module_declaration() {
+        let file = parse("mod foo;");
+        let path = parse_path_attr(first_mod(&file));
+        assert_eq!(path, None);
+    }
+
+    #[test]
+    fn cfg_options_populated_by_database_layer_is_honored() {
+        // Simulates what `db.cfg_options` must do: build a non-empty
+        // `CfgOptions` from outside this module, then check a parsed
+        // `#[cfg(..)]` predicate against it.
+        let file = parse("#[cfg(unix)] fn f() {}");
+        let cfg = parse_cfg_attrs(first_fn(&file));
+
+        let mut opts = CfgOptions::default();
+        assert!(!opts.check(&cfg), "unix should be disabled until enabled");
+
+        opts.insert_atom(SmolStr::from("unix"), None);
+        assert!(opts.check(&cfg), "unix should be honored once enabled");
+    }
+
+    #[test]
+    fn cfg_options_from_iter_builds_populated_set() {
+        let opts: CfgOptions = vec![
+            (SmolStr::from("unix"), None),
+            (SmolStr::from("target_arch"), Some(SmolStr::from("x86_64"))),
+        ]
+        .into_iter()
+        .collect();
+
+        assert!(opts.is_enabled(&SmolStr::from("unix"), None));
+        assert!(opts.is_enabled(&SmolStr::from("target_arch"), Some(&SmolStr::from("x86_64"))));
+        assert!(!opts.is_enabled(&SmolStr::from("windows"), None));
+    }
+}